@@ -0,0 +1,144 @@
+// clone.rs
+//
+// Makes a customized rootfs safe to clone: resets machine identity so each
+// clone gets its own machine-id/SSH host keys and re-runs cloud-init, and
+// builds the LXD metadata (including the templates/ LXD uses to regenerate
+// per-instance hostname/hosts) that ships alongside the tarball.
+
+use crate::run_command;
+use anyhow::{Context, Result};
+use chrono::Utc;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const HOSTNAME_TEMPLATE: &str = "{{ container.name }}\n";
+const HOSTS_TEMPLATE: &str = "127.0.0.1 localhost\n127.0.1.1 {{ container.name }}\n\n# The following lines are desirable for IPv6 capable hosts\n::1 ip6-localhost ip6-loopback\nfe00::0 ip6-localnet\nff00::0 ip6-mcastprefix\nff02::1 ip6-allnodes\nff02::2 ip6-allrouters\n";
+
+/// Resets machine identity inside `rootfs_dir` so cloning it (as a disk image
+/// or LXD container) doesn't leave every clone sharing a machine-id, SSH host
+/// keys, or cached cloud-init state: truncates `etc/machine-id`, drops
+/// `var/lib/dbus/machine-id` and the SSH host keys, and clears
+/// `var/lib/cloud/` so cloud-init re-runs on each clone's first boot.
+pub fn finalize_for_clone(rootfs_dir: &Path) -> Result<()> {
+    println!("Resetting machine identity for cloning");
+
+    let machine_id_path = rootfs_dir.join("etc/machine-id");
+    if machine_id_path.exists() {
+        fs::write(&machine_id_path, "").context("Failed to truncate etc/machine-id")?;
+    }
+
+    let dbus_machine_id_path = rootfs_dir.join("var/lib/dbus/machine-id");
+    if dbus_machine_id_path.exists() {
+        fs::remove_file(&dbus_machine_id_path)
+            .context("Failed to remove var/lib/dbus/machine-id")?;
+    }
+
+    let ssh_dir = rootfs_dir.join("etc/ssh");
+    if ssh_dir.is_dir() {
+        for entry in fs::read_dir(&ssh_dir).context("Failed to read etc/ssh")? {
+            let entry = entry.context("Failed to read entry in etc/ssh")?;
+            if entry.file_name().to_string_lossy().starts_with("ssh_host_") {
+                fs::remove_file(entry.path()).context(format!(
+                    "Failed to remove SSH host key {}",
+                    entry.path().display()
+                ))?;
+            }
+        }
+    }
+
+    let cloud_dir = rootfs_dir.join("var/lib/cloud");
+    if cloud_dir.is_dir() {
+        fs::remove_dir_all(&cloud_dir).context("Failed to clear var/lib/cloud")?;
+    }
+
+    Ok(())
+}
+
+/// Writes `metadata.yaml` (and, when `prepare_clone` is set, the
+/// `templates/hostname.tpl`/`templates/hosts.tpl` LXD uses to regenerate
+/// hostname/hosts per instance) into the current directory, for
+/// `create_lxd_tarball` to archive.
+fn generate_lxd_metadata(
+    package_name: &str,
+    release: &str,
+    proposed: bool,
+    prepare_clone: bool,
+) -> Result<()> {
+    let templates_section = if prepare_clone {
+        "templates:\n  /etc/hostname:\n    when:\n      - create\n      - copy\n    template: hostname.tpl\n  /etc/hosts:\n    when:\n      - create\n      - copy\n    template: hosts.tpl\n"
+    } else {
+        ""
+    };
+
+    let lxd_metadata = format!(
+        r#"architecture: x86_64
+creation_date: {}
+properties:
+  description: "Ubuntu {} with {}{}"
+  os: Ubuntu
+  release: "{}"
+{}"#,
+        Utc::now().timestamp(),
+        release,
+        package_name,
+        if proposed { " (proposed)" } else { "" },
+        release,
+        templates_section
+    );
+
+    fs::write("metadata.yaml", lxd_metadata).context("Failed to write LXD metadata")?;
+
+    if prepare_clone {
+        fs::create_dir_all("templates").context("Failed to create templates directory")?;
+        fs::write("templates/hostname.tpl", HOSTNAME_TEMPLATE)
+            .context("Failed to write templates/hostname.tpl")?;
+        fs::write("templates/hosts.tpl", HOSTS_TEMPLATE)
+            .context("Failed to write templates/hosts.tpl")?;
+    }
+
+    Ok(())
+}
+
+/// Packages `image_path` with the LXD metadata (and `templates/`, when
+/// `prepare_clone` is set) into an LXD container tarball.
+pub fn create_lxd_tarball(
+    image_path: PathBuf,
+    package_name: &str,
+    release: &str,
+    proposed: bool,
+    prepare_clone: bool,
+) -> Result<()> {
+    generate_lxd_metadata(package_name, release, proposed, prepare_clone)?;
+
+    let image_extension = image_path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .context("Image path has no file extension")?;
+
+    let tarball_name = image_path.clone().with_extension("tar.gz");
+    let mut tar_args = vec![
+        "--transform".to_string(),
+        format!("flags=r;s/.*\\.{}$/rootfs.img/", image_extension),
+        "-czf".to_string(),
+        tarball_name.to_str().unwrap().to_string(),
+        "metadata.yaml".to_string(),
+    ];
+    if prepare_clone {
+        tar_args.push("templates".to_string());
+    }
+    tar_args.push(image_path.to_str().unwrap().to_string());
+
+    run_command(
+        "tar",
+        &tar_args.iter().map(String::as_str).collect::<Vec<_>>(),
+        "Failed to create LXD tarball",
+    )?;
+
+    fs::remove_file("metadata.yaml").context("Failed to remove temporary metadata file")?;
+    if prepare_clone {
+        fs::remove_dir_all("templates")
+            .context("Failed to remove temporary templates directory")?;
+    }
+
+    Ok(())
+}
@@ -1,17 +1,24 @@
 // main.rs
+mod clone;
+mod mount;
+mod provision;
+mod repo;
+mod verify;
+
 use anyhow::{anyhow, Context, Result};
-use chrono::Utc;
-use clap::Parser;
+use clap::{CommandFactory, FromArgMatches, Parser};
+use provision::ProvisionStep;
 use std::fs;
 use std::path::PathBuf;
 use std::process::Command;
-use tempfile::tempdir;
+use std::time::Duration;
+use tempfile::{tempdir, TempDir};
 use tokio::fs::File;
 use tokio::io::AsyncWriteExt;
 
 /// Helper function to execute a shell command and check its success.
 /// Returns the command's stdout on success.
-fn run_command(command: &str, args: &[&str], error_msg: &str) -> Result<String> {
+pub(crate) fn run_command(command: &str, args: &[&str], error_msg: &str) -> Result<String> {
     println!("Executing: {} {}", command, args.join(" "));
     let output = Command::new(command)
         .args(args)
@@ -32,60 +39,30 @@ fn run_command(command: &str, args: &[&str], error_msg: &str) -> Result<String>
     Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
 }
 
-async fn download_image(url: &str, dest: &PathBuf) -> Result<()> {
+/// Fetches the raw bytes of `url` over HTTP(S).
+pub(crate) async fn fetch_bytes(url: &str) -> Result<Vec<u8>> {
     let response = reqwest::get(url)
         .await
         .context(format!("Failed to fetch URL: {}", url))?
         .error_for_status()
         .context(format!("Bad status code from URL: {}", url))?;
-
-    let mut dest = File::create(&dest)
-        .await
-        .context("Failed to create image file")?;
     let content = response
         .bytes()
         .await
         .context("Failed to read response bytes")?;
-    dest.write_all(&content)
-        .await
-        .context("Failed to write image content to file")?;
 
-    Ok(())
+    Ok(content.to_vec())
 }
 
-fn connect_image_to_nbd(image_path: &PathBuf, format: &str, nbd_device_path: &str) -> Result<()> {
-    // Ensure the nbd kernel module is loaded
-    run_command("modprobe", &["nbd"], "Failed to load nbd kernel module")
-        .context("Failed to load nbd kernel module")?;
-
-    // Connect the image to the NBD device
-    run_command(
-        "qemu-nbd",
-        &[
-            "--format",
-            format,
-            "--connect",
-            nbd_device_path,
-            image_path.to_str().unwrap(),
-        ],
-        "Failed to connect image to NBD device",
-    )
-    .context("Failed to connect image to NBD device")?;
-
-    // Sleep for a short duration to ensure the device is ready
-    std::thread::sleep(std::time::Duration::from_secs(2));
-
-    Ok(())
-}
-
-fn mount_partition(device: &str, mount_point: &PathBuf) -> Result<()> {
-    fs::create_dir_all(mount_point).context("Failed to create mount point directory")?;
+async fn download_image(url: &str, dest: &PathBuf) -> Result<()> {
+    let content = fetch_bytes(url).await?;
 
-    run_command(
-        "mount",
-        &[device, mount_point.to_str().unwrap()],
-        &format!("Failed to mount {} to {}", device, mount_point.display()),
-    )?;
+    let mut dest = File::create(&dest)
+        .await
+        .context("Failed to create image file")?;
+    dest.write_all(&content)
+        .await
+        .context("Failed to write image content to file")?;
 
     Ok(())
 }
@@ -118,93 +95,6 @@ fn restore_dns(rootfs_dir: &PathBuf) -> Result<()> {
     Ok(())
 }
 
-fn add_ppa(rootfs_dir: &PathBuf, ppa: &str) -> Result<()> {
-    let apt_add_repo_args = vec![
-        "-D",
-        rootfs_dir.to_str().unwrap(),
-        "apt-add-repository",
-        "--no-update",
-        "--yes",
-        ppa,
-    ];
-
-    run_command(
-        "systemd-nspawn",
-        &apt_add_repo_args,
-        "Failed to add proposed repository",
-    )?;
-    Ok(())
-}
-
-fn remove_ppa(rootfs_dir: &PathBuf, ppa: &str) -> Result<()> {
-    let apt_add_repo_args = vec![
-        "-D",
-        rootfs_dir.to_str().unwrap(),
-        "apt-add-repository",
-        "--yes",
-        "--remove",
-        ppa,
-    ];
-
-    run_command(
-        "systemd-nspawn",
-        &apt_add_repo_args,
-        "Failed to add proposed repository",
-    )?;
-    Ok(())
-}
-
-fn enable_proposed_repository(rootfs_dir: &PathBuf) -> Result<()> {
-    let apt_add_repo_args = vec![
-        "-D",
-        rootfs_dir.to_str().unwrap(),
-        "apt-add-repository",
-        "--yes",
-        "--no-update",
-        "--uri",
-        "http://archive.ubuntu.com/ubuntu/",
-        "--pocket",
-        "proposed",
-        "--component",
-        "main",
-        "--component",
-        "universe",
-    ];
-
-    run_command(
-        "systemd-nspawn",
-        &apt_add_repo_args,
-        "Failed to add proposed repository",
-    )?;
-    Ok(())
-}
-
-fn disable_proposed_repository(rootfs_dir: &PathBuf) -> Result<()> {
-    let apt_add_repo_args = vec![
-        "-D",
-        rootfs_dir.to_str().unwrap(),
-        "apt-add-repository",
-        "--yes",
-        "--uri",
-        "http://archive.ubuntu.com/ubuntu/",
-        "--pocket",
-        "proposed",
-        "--component",
-        "main",
-        "--component",
-        "universe",
-        "--remove",
-    ];
-
-    run_command(
-        "systemd-nspawn",
-        &apt_add_repo_args,
-        "Failed to remove proposed repository",
-    )?;
-
-    Ok(())
-}
-
 fn get_release(rootfs_dir: &PathBuf) -> Result<String> {
     let os_release_content = fs::read_to_string(rootfs_dir.join("etc/os-release"))
         .context("Failed to read /etc/os-release")?;
@@ -218,105 +108,70 @@ fn get_release(rootfs_dir: &PathBuf) -> Result<String> {
     Ok(release)
 }
 
-fn install_package(
-    rootfs_dir: &PathBuf,
-    package_name: &str,
-    release: &str,
-    proposed: bool,
-    ppa: Option<String>,
-) -> Result<()> {
-    let package_name = if proposed {
-        println!("Enabling -proposed repository...");
-        enable_proposed_repository(&rootfs_dir)?;
-        &format!("{}/{}-proposed", package_name, release)
-    } else {
-        package_name
-    };
-
-    if let Some(ppa_name) = ppa.as_ref() {
-        println!("Adding ppa {}", &ppa_name);
-        add_ppa(&rootfs_dir, &ppa_name)?;
+/// File extension to use for a given `qemu-img` output format.
+fn output_format_extension(format: &str) -> &str {
+    match format {
+        "raw" => "img",
+        other => other,
     }
+}
 
-    run_command(
-        "systemd-nspawn",
-        &[
-            "-D",
-            rootfs_dir.to_str().unwrap(),
-            "apt-get",
-            "update",
-            "-y",
-        ],
-        &format!("Failed to install package {}", package_name),
-    )?;
-
-    run_command(
-        "systemd-nspawn",
-        &[
-            "-D",
-            rootfs_dir.to_str().unwrap(),
-            "apt-get",
-            "install",
-            "-y",
-            package_name,
-        ],
-        &format!("Failed to install package {}", package_name),
-    )?;
-
-    if proposed {
-        println!("Disabling -proposed repository...");
-        disable_proposed_repository(&rootfs_dir)?;
-    }
-    if let Some(ppa_name) = ppa.as_ref() {
-        println!("Removing PPA {}", &ppa_name);
-        remove_ppa(&rootfs_dir, &ppa_name)?;
-    }
-    Ok(())
+/// Everything needed to turn the finished working image into its final artifacts.
+struct ArtifactSpec<'a> {
+    image_name: &'a str,
+    package_name: &'a str,
+    proposed: bool,
+    release: &'a str,
+    working_image_path: &'a PathBuf,
+    working_image_format: &'a str,
+    output_formats: &'a [String],
+    lxd: bool,
+    prepare_clone: bool,
 }
 
-fn generate_lxd_metadata(package_name: &str, release: &str, proposed: bool) -> Result<()> {
-    let lxd_metadata = format!(
-        r#"architecture: x86_64
-creation_date: {}
-properties:
-  description: "Ubuntu {} with {}{}"
-  os: Ubuntu
-  release: "{}"
-"#,
-        Utc::now().timestamp(),
-        release,
-        package_name,
-        if proposed { " (proposed)" } else { "" },
-        release
-    );
+/// Converts the finished working image into each requested output format,
+/// writing `{image_name}_{package_name}{_proposed}.{ext}` for every format.
+/// When `lxd` is set, each artifact is wrapped in an LXD tarball instead of
+/// being left as a bare disk image.
+fn emit_artifacts(spec: &ArtifactSpec) -> Result<()> {
+    let proposed_tag = if spec.proposed { "_proposed" } else { "" };
+
+    for format in spec.output_formats {
+        let artifact_path = PathBuf::from(format!(
+            "{}_{}{}.{}",
+            spec.image_name,
+            spec.package_name,
+            proposed_tag,
+            output_format_extension(format)
+        ));
 
-    fs::write("metadata.yaml", lxd_metadata).context("Failed to write LXD metadata")
-}
+        println!("Writing {} image to {}", format, artifact_path.display());
+        run_command(
+            "qemu-img",
+            &[
+                "convert",
+                "-f",
+                spec.working_image_format,
+                "-O",
+                format,
+                spec.working_image_path.to_str().unwrap(),
+                artifact_path.to_str().unwrap(),
+            ],
+            &format!("Failed to convert working image to {}", format),
+        )?;
 
-fn create_lxd_tarball(
-    image_path: PathBuf,
-    package_name: &str,
-    release: &str,
-    proposed: bool,
-) -> Result<()> {
-    // Generate LXD metadata
-    generate_lxd_metadata(package_name, release, proposed)?;
-
-    let tarball_name = image_path.clone().with_extension("tar.gz");
-    run_command(
-        "tar",
-        &[
-            "--transform",
-            &format!("flags=r;s/.*.img/rootfs.img/"),
-            "-czf",
-            tarball_name.to_str().unwrap(),
-            "metadata.yaml",
-            image_path.to_str().unwrap(),
-        ],
-        "Failed to create LXD tarball",
-    )?;
-
-    fs::remove_file("metadata.yaml").context("Failed to remove temporary metadata file")?;
+        if spec.lxd {
+            clone::create_lxd_tarball(
+                artifact_path.clone(),
+                spec.package_name,
+                spec.release,
+                spec.proposed,
+                spec.prepare_clone,
+            )?;
+            fs::remove_file(&artifact_path)
+                .context("Failed to remove temporary image file")?;
+        }
+    }
 
     Ok(())
 }
@@ -333,18 +188,84 @@ struct Cli {
     #[arg(long, short, default_value_t = false)]
     lxd: bool,
 
+    /// Reset machine-id, SSH host keys and cloud-init state so clones of the
+    /// image/container get fresh identity on first boot (implied by --lxd)
+    #[arg(long, default_value_t = false)]
+    prepare_clone: bool,
+
     /// URL or path to the Ubuntu cloud image
     image_uri: String,
-    /// Name of the package to install from -proposed
-    package_name: String,
+    /// Name(s) of the package(s) to install from -proposed
+    #[arg(required = true)]
+    package_names: Vec<String>,
 
-    /// Format of the binary image (qcow2, raw, vpc...)
+    /// Format of the input binary image (qcow2, raw, vpc...)
     #[arg(long, default_value_t = String::from("qcow2"))]
     image_format: String,
 
-    /// Enable this PPA before installing package
-    #[arg(long, value_name = "ppa:owner/name")]
-    ppa: Option<String>,
+    /// Comma-separated list of formats to emit (qcow2, vmdk, vdi, raw...)
+    #[arg(long, value_delimiter = ',', default_value = "qcow2")]
+    output_format: Vec<String>,
+
+    /// Enable this PPA before installing packages (repeatable)
+    #[arg(long = "ppa", value_name = "ppa:owner/name")]
+    ppa: Vec<String>,
+
+    /// Add this APT repository ("deb ..." line) before installing packages (repeatable)
+    #[arg(long = "apt-repo", value_name = "deb line")]
+    apt_repo: Vec<String>,
+
+    /// GPG key (URL or local path) for the APT repo at the same position (repeatable)
+    #[arg(long = "apt-key", value_name = "url-or-path")]
+    apt_key: Vec<String>,
+
+    /// Run this shell command inside the rootfs after installing the package (repeatable)
+    #[arg(long = "run-command", value_name = "shell command")]
+    run_command: Vec<String>,
+
+    /// Copy and run this script inside the rootfs after installing the package (repeatable)
+    #[arg(long = "run-script", value_name = "path")]
+    run_script: Vec<PathBuf>,
+
+    /// Boot the finished image in QEMU and verify the package installed correctly
+    #[arg(long, default_value_t = false)]
+    verify: bool,
+
+    /// How long to wait, in seconds, for the verify boot to confirm the package
+    #[arg(long, default_value_t = 300)]
+    verify_timeout: u64,
+
+    /// Script to run inside the VM for --verify, instead of the default dpkg check
+    #[arg(long, value_name = "path")]
+    verify_script: Option<PathBuf>,
+}
+
+/// Interleaves `--run-command` and `--run-script` values by the order they
+/// were given on the command line, rather than grouping them by flag.
+fn provisioning_steps(
+    matches: &clap::ArgMatches,
+    run_commands: &[String],
+    run_scripts: &[PathBuf],
+) -> Vec<ProvisionStep> {
+    let mut steps: Vec<(usize, ProvisionStep)> = matches
+        .indices_of("run_command")
+        .into_iter()
+        .flatten()
+        .zip(run_commands.iter().cloned())
+        .map(|(index, command)| (index, ProvisionStep::Command(command)))
+        .collect();
+
+    steps.extend(
+        matches
+            .indices_of("run_script")
+            .into_iter()
+            .flatten()
+            .zip(run_scripts.iter().cloned())
+            .map(|(index, script)| (index, ProvisionStep::Script(script))),
+    );
+
+    steps.sort_by_key(|(index, _)| *index);
+    steps.into_iter().map(|(_, step)| step).collect()
 }
 
 #[tokio::main]
@@ -354,81 +275,108 @@ async fn main() -> Result<()> {
         eprintln!("Panic occurred: {:?}", panic_info);
     }));
 
-    let cli = Cli::parse();
+    // Parsed through raw ArgMatches (rather than Cli::parse()) so we can
+    // recover the relative order --run-command/--run-script were given in.
+    let matches = Cli::command().get_matches();
+    let cli = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+
+    let provision_steps = provisioning_steps(&matches, &cli.run_command, &cli.run_script);
+
+    let apt_keys: Vec<Option<String>> = cli
+        .apt_repo
+        .iter()
+        .enumerate()
+        .map(|(index, _)| cli.apt_key.get(index).cloned())
+        .collect();
+    let apt_config = repo::AptConfig {
+        proposed: cli.proposed,
+        ppas: cli.ppa,
+        apt_repos: cli.apt_repo,
+        apt_keys,
+    };
+
+    let prepare_clone = cli.prepare_clone || cli.lxd;
+
+    // Validate flag combinations before doing any of the expensive work
+    // below (building the image, and potentially booting it for --verify).
+    if cli.lxd && cli.output_format != vec!["qcow2".to_string()] {
+        return Err(anyhow!(
+            "Cannot create LXD tarball from output format(s) '{}', only qcow2 is supported",
+            cli.output_format.join(",")
+        ));
+    }
 
     // Call the customize_image function
     let image_info = customize_image(
         &cli.image_uri,
         &cli.image_format,
-        &cli.package_name,
-        cli.proposed,
-        cli.ppa,
+        &cli.package_names,
+        &apt_config,
+        &provision_steps,
+        prepare_clone,
     )
     .await?;
 
-    if cli.lxd {
-        if cli.image_format != "qcow2" {
-            return Err(anyhow!(
-                "Cannot create LXD tarbal from '{}' image",
-                cli.image_format
-            ));
-        }
-
-        // Generate LXD metadata
-        create_lxd_tarball(
-            image_info.image_path.clone(),
-            &cli.package_name,
-            &image_info.release,
+    if cli.verify {
+        println!("Verifying package installation by booting the image under QEMU");
+        verify::verify_image(
+            &image_info.image_path,
+            &image_info.image_format,
+            &cli.package_names,
             cli.proposed,
+            &image_info.release,
+            Duration::from_secs(cli.verify_timeout),
+            cli.verify_script.as_deref(),
         )?;
-        fs::remove_file(image_info.image_path).context("Failed to remove temporary image file")?;
     }
 
+    let image_name = cli
+        .image_uri
+        .split('/')
+        .last()
+        .unwrap()
+        .trim_end_matches(".img");
+    let package_label = cli.package_names.join("+");
+
+    emit_artifacts(&ArtifactSpec {
+        image_name,
+        package_name: &package_label,
+        proposed: cli.proposed,
+        release: &image_info.release,
+        working_image_path: &image_info.image_path,
+        working_image_format: &image_info.image_format,
+        output_formats: &cli.output_format,
+        lxd: cli.lxd,
+        prepare_clone,
+    })?;
+
     Ok(())
 }
 
 struct ImageInfo {
     image_path: PathBuf,
+    image_format: String,
     release: String,
+    // Keeps the working image's temporary directory alive until the
+    // caller is done emitting artifacts from it.
+    _temp_dir: TempDir,
 }
 
 async fn customize_image(
     image_uri: &str,
     image_format: &str,
-    package_name: &str,
-    proposed: bool,
-    ppa: Option<String>,
+    package_names: &[String],
+    apt_config: &repo::AptConfig,
+    provision_steps: &[ProvisionStep],
+    prepare_clone: bool,
 ) -> Result<ImageInfo> {
     println!("Starting VM image processing for URL: {}", image_uri);
-    println!("Package to install: {}", package_name);
+    println!("Package(s) to install: {}", package_names.join(", "));
 
     // Create a temporary directory for downloads and mounts
     let temp_base_dir = tempdir().context("Failed to create temporary base directory")?;
     let image_path = temp_base_dir.path().join("vm_image.img");
     let rootfs_dir = temp_base_dir.path().join("rootfs");
-    let boot_dir = rootfs_dir.join("boot");
-    let boot_efi_dir = boot_dir.join("efi");
-
-    // Ensure cleanup happens even if errors occur
-    let cleanup_guard = CleanupGuard {
-        nbd_device_path: None,
-        rootfs_dir: rootfs_dir.clone(),
-    };
-
-    // Extract the image name from the UR/L
-    let image_name = image_uri
-        .split('/')
-        .last()
-        .unwrap()
-        .trim_end_matches(".img");
-
-    // Copy image to current directory
-    // file format: {image_name}-{package_name}-proposed.img
-    let proposed_tag = if proposed { "_proposed" } else { "" };
-    let final_image_path = PathBuf::from(format!(
-        "{}_{}{}.img",
-        image_name, package_name, proposed_tag
-    ));
 
     // Determine if image_url is a URL or a local file path
     if image_uri.starts_with("http://") || image_uri.starts_with("https://") {
@@ -446,36 +394,19 @@ async fn customize_image(
     }
 
     let release: String;
+    let working_image_path: PathBuf;
+    let working_image_format: String;
 
-    // Use a block to ensure `cleanup_guard` is dropped at the end of `main`
+    // `mount_guard` unmounts and detaches the backing device when dropped,
+    // so this happens even if an error occurs below.
     {
-        // Mutate the cleanup_guard within the block
-        let mut cleanup_guard = cleanup_guard;
-        let nbd_device_path = "/dev/nbd0";
-
-        println!("Attaching image to loop device using qemu-nbd");
-        connect_image_to_nbd(&image_path, image_format, nbd_device_path)?;
-
-        cleanup_guard.nbd_device_path = Some(nbd_device_path.to_string());
-
-        println!("Mounting partitions");
-        // Create mount points
         fs::create_dir_all(&rootfs_dir).context("Failed to create rootfs directory")?;
 
-        // Mount /dev/loopXp1 to rootfs
-        mount_partition(&format!("{}p1", nbd_device_path), &rootfs_dir)?;
-
-        // Mount /dev/loopXp13 to rootfs/boot
-        let mount_result = mount_partition(&format!("{}p13", nbd_device_path), &boot_dir);
-
-        if mount_result.is_err() {
-            // Noble has it on p16 instead of p13
-            // Mount /dev/loopXp16 to rootfs/boot
-            let _ = mount_partition(&format!("{}p13", nbd_device_path), &boot_dir);
-        }
-
-        // Mount /dev/loopXp15 to rootfs/boot/efi
-        mount_partition(&format!("{}p15", nbd_device_path), &boot_efi_dir)?;
+        println!("Attaching image to a block device");
+        let mount_guard =
+            mount::attach_and_mount(&image_path, image_format, temp_base_dir.path(), &rootfs_dir)?;
+        working_image_path = mount_guard.working_image_path.clone();
+        working_image_format = mount_guard.working_image_format.clone();
 
         println!("Configuring DNS settings");
         configure_dns(&rootfs_dir)?;
@@ -484,57 +415,25 @@ async fn customize_image(
         // Determine the release name from the image URL
         release = get_release(&rootfs_dir)?;
 
-        // Install the specified package
-        println!("Installing package: {}...", package_name);
-        install_package(&rootfs_dir, package_name, &release, proposed, ppa)?;
-        println!("Package '{}' installed successfully.", package_name);
+        // Install the specified package(s)
+        println!("Installing package(s): {}...", package_names.join(", "));
+        repo::install_packages(&rootfs_dir, package_names, &release, apt_config).await?;
+        println!("Package(s) '{}' installed successfully.", package_names.join(", "));
 
-        restore_dns(&rootfs_dir)?;
-    } // `cleanup_guard` is dropped here, triggering cleanup
+        provision::run_steps(&rootfs_dir, provision_steps)?;
 
-    run_command(
-        "cp",
-        &[
-            image_path.to_str().unwrap(),
-            final_image_path.to_str().unwrap(),
-        ],
-        "Failed to copy final image.",
-    )?;
+        if prepare_clone {
+            clone::finalize_for_clone(&rootfs_dir)?;
+        }
+
+        restore_dns(&rootfs_dir)?;
+    } // `mount_guard` is dropped here, triggering cleanup
 
     Ok(ImageInfo {
-        image_path: final_image_path,
+        image_path: working_image_path,
+        image_format: working_image_format,
         release,
+        _temp_dir: temp_base_dir,
     })
 }
 
-struct CleanupGuard {
-    nbd_device_path: Option<String>,
-    rootfs_dir: PathBuf,
-}
-
-impl Drop for CleanupGuard {
-    fn drop(&mut self) {
-        println!("Cleaning up... ");
-
-        if self.rootfs_dir.is_dir() {
-            println!("Umounting rootfs directory: {:?}", self.rootfs_dir);
-            let _ = run_command(
-                "umount",
-                &["-R", self.rootfs_dir.to_str().unwrap()],
-                "Failed to unmount rootfs (during cleanup)",
-            );
-        }
-
-        // Detach loop device
-        if let Some(ref dev) = self.nbd_device_path {
-            let _ = run_command(
-                "qemu-nbd",
-                &["--disconnect", dev],
-                "Failed to disconnect nbd device (during cleanup)",
-            )
-            .unwrap();
-        }
-
-        println!("Cleanup complete.");
-    }
-}
@@ -0,0 +1,425 @@
+// mount.rs
+//
+// Attaches a disk image to a block device and mounts its partitions.
+//
+// Loop devices can only attach raw images directly, so qcow2 (and other
+// non-raw) inputs are first converted to raw via `qemu-img convert`. If
+// loop attachment still fails for some reason, we fall back to the old
+// qemu-nbd path rather than hard-failing.
+//
+// Partitions are found by role rather than by fixed number, since the
+// partition layout (and numbering) differs across Ubuntu releases: we ask
+// `lsblk` for each partition's label/filesystem and pick the root, boot and
+// ESP partitions from those, instead of assuming e.g. p1/p13/p15.
+
+use crate::run_command;
+use anyhow::{anyhow, Context, Result};
+use loopdev::{LoopControl, LoopDevice};
+use serde::Deserialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+use sys_mount::{Mount, UnmountDrop, UnmountFlags};
+
+/// A block device backing the image being customized, however it was attached.
+enum BlockDevice {
+    Loop(LoopDevice),
+    Nbd(String),
+}
+
+impl BlockDevice {
+    fn path(&self) -> String {
+        match self {
+            BlockDevice::Loop(dev) => dev.path().unwrap().to_string_lossy().to_string(),
+            BlockDevice::Nbd(path) => path.clone(),
+        }
+    }
+}
+
+/// Converts `image_path` to a raw image under `dest_dir` if it isn't raw already.
+/// Returns the path to the raw image to attach (which may just be `image_path`).
+fn ensure_raw_image(image_path: &Path, format: &str, dest_dir: &Path) -> Result<PathBuf> {
+    if format == "raw" {
+        return Ok(image_path.to_path_buf());
+    }
+
+    let raw_path = dest_dir.join("vm_image.raw");
+    println!("Converting {} image to raw for loop-mounting", format);
+    run_command(
+        "qemu-img",
+        &[
+            "convert",
+            "-O",
+            "raw",
+            "-f",
+            format,
+            image_path.to_str().unwrap(),
+            raw_path.to_str().unwrap(),
+        ],
+        "Failed to convert image to raw",
+    )?;
+
+    Ok(raw_path)
+}
+
+/// Attaches `image_path` (already raw) to a free loop device.
+fn attach_loop_device(image_path: &Path) -> Result<LoopDevice> {
+    let loop_control = LoopControl::open().context("Failed to open /dev/loop-control")?;
+    let loop_device = loop_control
+        .next_free()
+        .context("Failed to find a free loop device")?;
+    loop_device
+        .with()
+        .part_scan(true)
+        .attach(image_path)
+        .context(format!("Failed to attach {} to loop device", image_path.display()))?;
+
+    Ok(loop_device)
+}
+
+/// Falls back to attaching `image_path` via qemu-nbd when loop attachment fails.
+fn attach_nbd_device(image_path: &Path, format: &str) -> Result<String> {
+    println!("Falling back to qemu-nbd for device attachment");
+    run_command("modprobe", &["nbd"], "Failed to load nbd kernel module")?;
+
+    let nbd_device_path = "/dev/nbd0";
+    run_command(
+        "qemu-nbd",
+        &[
+            "--format",
+            format,
+            "--connect",
+            nbd_device_path,
+            image_path.to_str().unwrap(),
+        ],
+        "Failed to connect image to NBD device",
+    )?;
+    std::thread::sleep(std::time::Duration::from_secs(2));
+
+    Ok(nbd_device_path.to_string())
+}
+
+/// Attaches `image_path` to a block device, converting to raw first if needed.
+/// Returns the attached device, the device-node path to partition off of, and
+/// the file (and its format) that mounting actually exposed for writing:
+/// loop-mounting a non-raw image writes through to the converted raw copy
+/// under `temp_dir`, not `image_path` itself, while the qemu-nbd fallback
+/// attaches `image_path` directly in its original format.
+fn attach_image(
+    image_path: &Path,
+    format: &str,
+    temp_dir: &Path,
+) -> Result<(BlockDevice, String, PathBuf, String)> {
+    let raw_path = ensure_raw_image(image_path, format, temp_dir)?;
+
+    let (block_device, working_image_path, working_image_format) =
+        match attach_loop_device(&raw_path) {
+            Ok(loop_device) => (BlockDevice::Loop(loop_device), raw_path, "raw".to_string()),
+            Err(err) => {
+                eprintln!("Loop-device attachment failed ({}), trying qemu-nbd", err);
+                let nbd_device_path = attach_nbd_device(image_path, format)?;
+                (
+                    BlockDevice::Nbd(nbd_device_path),
+                    image_path.to_path_buf(),
+                    format.to_string(),
+                )
+            }
+        };
+    let device_path = block_device.path();
+
+    Ok((block_device, device_path, working_image_path, working_image_format))
+}
+
+#[derive(Deserialize)]
+struct LsblkReport {
+    blockdevices: Vec<LsblkDevice>,
+}
+
+#[derive(Deserialize)]
+struct LsblkDevice {
+    name: String,
+    partlabel: Option<String>,
+    fstype: Option<String>,
+    size: Option<String>,
+    #[serde(default)]
+    children: Vec<LsblkDevice>,
+}
+
+/// The partitions to mount for a rootfs, discovered by role rather than by
+/// fixed partition number. `boot` and `efi` are optional since not every
+/// image layout has a separate /boot or ESP partition.
+struct PartitionLayout {
+    root: String,
+    boot: Option<String>,
+    efi: Option<String>,
+}
+
+/// Inspects the partitions on `device_path` via `lsblk` and picks the root,
+/// boot and ESP partitions by label/filesystem: the `cloudimg-rootfs`
+/// partition (falling back to the largest ext4 partition) is root, `BOOT` is
+/// /boot, and the `EFI System` (or first vfat) partition is the ESP.
+fn discover_partitions(device_path: &str) -> Result<PartitionLayout> {
+    let output = run_command(
+        "lsblk",
+        &["-J", "-b", "-o", "NAME,PARTLABEL,FSTYPE,SIZE", device_path],
+        "Failed to list partitions with lsblk",
+    )?;
+
+    let report: LsblkReport =
+        serde_json::from_str(&output).context("Failed to parse lsblk output")?;
+
+    select_partitions(report, device_path)
+}
+
+/// Picks root/boot/efi partitions out of an already-parsed `lsblk` report.
+/// Pulled out of `discover_partitions` so the selection logic can be
+/// exercised directly against fixture JSON without shelling out to `lsblk`.
+fn select_partitions(report: LsblkReport, device_path: &str) -> Result<PartitionLayout> {
+    let disk = report
+        .blockdevices
+        .into_iter()
+        .next()
+        .ok_or_else(|| anyhow!("lsblk reported no block devices for {}", device_path))?;
+
+    let mut root = None;
+    let mut boot = None;
+    let mut efi = None;
+    let mut largest_ext4: Option<(u64, String)> = None;
+
+    for partition in disk.children {
+        let path = format!("/dev/{}", partition.name);
+        let label = partition.partlabel.as_deref().unwrap_or("");
+        let fstype = partition.fstype.as_deref().unwrap_or("");
+
+        if label.eq_ignore_ascii_case("cloudimg-rootfs") {
+            root = Some(path.clone());
+        } else if label.eq_ignore_ascii_case("BOOT") && fstype == "ext4" {
+            boot = Some(path.clone());
+        } else if label.eq_ignore_ascii_case("EFI System") || (fstype == "vfat" && efi.is_none())
+        {
+            efi = Some(path.clone());
+        }
+
+        if fstype == "ext4" {
+            let size: u64 = partition.size.as_deref().unwrap_or("0").parse().unwrap_or(0);
+            if largest_ext4.as_ref().is_none_or(|(largest, _)| size > *largest) {
+                largest_ext4 = Some((size, path));
+            }
+        }
+    }
+
+    let root = root
+        .or_else(|| largest_ext4.map(|(_, path)| path))
+        .ok_or_else(|| anyhow!("Could not find a root partition on {}", device_path))?;
+
+    Ok(PartitionLayout { root, boot, efi })
+}
+
+fn mount_partition(device: &str, mount_point: &Path) -> Result<UnmountDrop<Mount>> {
+    fs::create_dir_all(mount_point).context("Failed to create mount point directory")?;
+
+    let mount = Mount::builder()
+        .mount(device, mount_point)
+        .context(format!("Failed to mount {} to {}", device, mount_point.display()))?;
+
+    Ok(mount.into_unmount_drop(UnmountFlags::DETACH))
+}
+
+/// Attaches `image_path` and mounts its root, boot and EFI partitions under
+/// `rootfs_dir`. Returns a guard that unmounts and detaches everything, in
+/// the correct order, when dropped. The guard's `working_image_path`/
+/// `working_image_format` report the file (and format) that was actually
+/// mounted, which is not always `image_path`/`format` (see `attach_image`).
+pub fn attach_and_mount(
+    image_path: &Path,
+    format: &str,
+    temp_dir: &Path,
+    rootfs_dir: &Path,
+) -> Result<MountGuard> {
+    let (block_device, device_path, working_image_path, working_image_format) =
+        attach_image(image_path, format, temp_dir)?;
+
+    println!("Discovering partition layout");
+    let layout = discover_partitions(&device_path)?;
+
+    println!("Mounting partitions");
+    let boot_dir = rootfs_dir.join("boot");
+    let boot_efi_dir = boot_dir.join("efi");
+
+    let root_mount = mount_partition(&layout.root, rootfs_dir)?;
+
+    let boot_mount = layout
+        .boot
+        .map(|boot_device| mount_partition(&boot_device, &boot_dir))
+        .transpose()?;
+
+    let efi_mount = layout
+        .efi
+        .map(|efi_device| mount_partition(&efi_device, &boot_efi_dir))
+        .transpose()?;
+
+    Ok(MountGuard {
+        efi_mount,
+        boot_mount,
+        root_mount: Some(root_mount),
+        block_device: Some(block_device),
+        working_image_path,
+        working_image_format,
+    })
+}
+
+/// Ensures mounts are torn down and the backing device detached, in the
+/// right order, even if an error occurs while customizing the image.
+pub struct MountGuard {
+    efi_mount: Option<UnmountDrop<Mount>>,
+    boot_mount: Option<UnmountDrop<Mount>>,
+    root_mount: Option<UnmountDrop<Mount>>,
+    block_device: Option<BlockDevice>,
+    /// The file that was actually mounted and mutated. See `attach_image`.
+    pub working_image_path: PathBuf,
+    /// The format of `working_image_path` (not always the input's format).
+    pub working_image_format: String,
+}
+
+impl Drop for MountGuard {
+    fn drop(&mut self) {
+        println!("Cleaning up...");
+
+        // Drop innermost mounts first so outer ones aren't busy.
+        self.efi_mount.take();
+        self.boot_mount.take();
+        self.root_mount.take();
+
+        match self.block_device.take() {
+            Some(BlockDevice::Loop(loop_device)) => {
+                if let Err(err) = loop_device.detach() {
+                    eprintln!("Failed to detach loop device (during cleanup): {}", err);
+                }
+            }
+            Some(BlockDevice::Nbd(device_path)) => {
+                let _ = run_command(
+                    "qemu-nbd",
+                    &["--disconnect", &device_path],
+                    "Failed to disconnect nbd device (during cleanup)",
+                );
+            }
+            None => {}
+        }
+
+        println!("Cleanup complete.");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Focal/Jammy-style layout: rootfs found by its `cloudimg-rootfs`
+    /// PARTLABEL, with separate BOOT and EFI System partitions.
+    #[test]
+    fn selects_rootfs_boot_and_efi_by_label() {
+        let report: LsblkReport = serde_json::from_str(
+            r#"{
+                "blockdevices": [
+                    {
+                        "name": "loop0",
+                        "partlabel": null,
+                        "fstype": null,
+                        "size": null,
+                        "children": [
+                            {"name": "loop0p1", "partlabel": "EFI System", "fstype": "vfat", "size": "134217728"},
+                            {"name": "loop0p13", "partlabel": "BIOS Boot Partition", "fstype": null, "size": "1048576"},
+                            {"name": "loop0p14", "partlabel": "BOOT", "fstype": "ext4", "size": "1342177280"},
+                            {"name": "loop0p15", "partlabel": "cloudimg-rootfs", "fstype": "ext4", "size": "2361393152"}
+                        ]
+                    }
+                ]
+            }"#,
+        )
+        .unwrap();
+
+        let layout = select_partitions(report, "/dev/loop0").unwrap();
+
+        assert_eq!(layout.root, "/dev/loop0p15");
+        assert_eq!(layout.boot.as_deref(), Some("/dev/loop0p14"));
+        assert_eq!(layout.efi.as_deref(), Some("/dev/loop0p1"));
+    }
+
+    /// Noble-style layout with no `cloudimg-rootfs` PARTLABEL at all: root
+    /// should fall back to the largest ext4 partition.
+    #[test]
+    fn falls_back_to_largest_ext4_when_no_rootfs_label() {
+        let report: LsblkReport = serde_json::from_str(
+            r#"{
+                "blockdevices": [
+                    {
+                        "name": "loop0",
+                        "partlabel": null,
+                        "fstype": null,
+                        "size": null,
+                        "children": [
+                            {"name": "loop0p1", "partlabel": "EFI System", "fstype": "vfat", "size": "134217728"},
+                            {"name": "loop0p2", "partlabel": null, "fstype": "ext4", "size": "536870912"},
+                            {"name": "loop0p3", "partlabel": null, "fstype": "ext4", "size": "3221225472"}
+                        ]
+                    }
+                ]
+            }"#,
+        )
+        .unwrap();
+
+        let layout = select_partitions(report, "/dev/loop0").unwrap();
+
+        assert_eq!(layout.root, "/dev/loop0p3");
+        assert_eq!(layout.boot, None);
+        assert_eq!(layout.efi.as_deref(), Some("/dev/loop0p1"));
+    }
+
+    /// Minimal image with a single ext4 partition and no /boot or ESP.
+    #[test]
+    fn handles_missing_boot_and_efi_partitions() {
+        let report: LsblkReport = serde_json::from_str(
+            r#"{
+                "blockdevices": [
+                    {
+                        "name": "loop0",
+                        "partlabel": null,
+                        "fstype": null,
+                        "size": null,
+                        "children": [
+                            {"name": "loop0p1", "partlabel": "cloudimg-rootfs", "fstype": "ext4", "size": "2361393152"}
+                        ]
+                    }
+                ]
+            }"#,
+        )
+        .unwrap();
+
+        let layout = select_partitions(report, "/dev/loop0").unwrap();
+
+        assert_eq!(layout.root, "/dev/loop0p1");
+        assert_eq!(layout.boot, None);
+        assert_eq!(layout.efi, None);
+    }
+
+    #[test]
+    fn errors_when_no_root_candidate_exists() {
+        let report: LsblkReport = serde_json::from_str(
+            r#"{
+                "blockdevices": [
+                    {
+                        "name": "loop0",
+                        "partlabel": null,
+                        "fstype": null,
+                        "size": null,
+                        "children": [
+                            {"name": "loop0p1", "partlabel": "EFI System", "fstype": "vfat", "size": "134217728"}
+                        ]
+                    }
+                ]
+            }"#,
+        )
+        .unwrap();
+
+        assert!(select_partitions(report, "/dev/loop0").is_err());
+    }
+}
@@ -0,0 +1,73 @@
+// provision.rs
+//
+// Runs user-supplied commands and scripts inside the mounted rootfs via
+// systemd-nspawn, after the package install and before DNS is restored.
+
+use crate::run_command;
+use anyhow::{anyhow, Context, Result};
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+/// A single post-install provisioning step, in the order it should run.
+pub enum ProvisionStep {
+    Command(String),
+    Script(PathBuf),
+}
+
+/// Runs every provisioning step, in order, inside `rootfs_dir`.
+pub fn run_steps(rootfs_dir: &Path, steps: &[ProvisionStep]) -> Result<()> {
+    for step in steps {
+        match step {
+            ProvisionStep::Command(command) => run_command_in_rootfs(rootfs_dir, command)?,
+            ProvisionStep::Script(script_path) => run_script_in_rootfs(rootfs_dir, script_path)?,
+        }
+    }
+    Ok(())
+}
+
+fn run_command_in_rootfs(rootfs_dir: &Path, command: &str) -> Result<()> {
+    println!("Running command inside rootfs: {}", command);
+    run_command(
+        "systemd-nspawn",
+        &["-D", rootfs_dir.to_str().unwrap(), "/bin/sh", "-c", command],
+        &format!("Failed to run command '{}' inside rootfs", command),
+    )?;
+    Ok(())
+}
+
+fn run_script_in_rootfs(rootfs_dir: &Path, script_path: &Path) -> Result<()> {
+    let script_name = script_path
+        .file_name()
+        .ok_or_else(|| anyhow!("Invalid script path: {}", script_path.display()))?;
+    let staged_path = rootfs_dir.join("tmp").join(script_name);
+
+    println!("Copying script {} into rootfs", script_path.display());
+    fs::copy(script_path, &staged_path).context(format!(
+        "Failed to copy script {} into rootfs",
+        script_path.display()
+    ))?;
+
+    let mut permissions = fs::metadata(&staged_path)
+        .context("Failed to read staged script metadata")?
+        .permissions();
+    permissions.set_mode(0o755);
+    fs::set_permissions(&staged_path, permissions)
+        .context("Failed to make staged script executable")?;
+
+    let in_rootfs_path = PathBuf::from("/tmp").join(script_name);
+    let result = run_command(
+        "systemd-nspawn",
+        &[
+            "-D",
+            rootfs_dir.to_str().unwrap(),
+            "/bin/sh",
+            in_rootfs_path.to_str().unwrap(),
+        ],
+        &format!("Failed to run script {} inside rootfs", script_path.display()),
+    );
+
+    fs::remove_file(&staged_path).context("Failed to remove staged script from rootfs")?;
+
+    result.map(|_| ())
+}
@@ -0,0 +1,294 @@
+// repo.rs
+//
+// Manages the APT sources used while customizing a rootfs: the -proposed
+// pocket, PPAs, and arbitrary third-party repositories with their GPG keys.
+
+use crate::{fetch_bytes, run_command};
+use anyhow::{Context, Result};
+use std::fs;
+use std::path::Path;
+
+fn enable_proposed_repository(rootfs_dir: &Path) -> Result<()> {
+    run_command(
+        "systemd-nspawn",
+        &[
+            "-D",
+            rootfs_dir.to_str().unwrap(),
+            "apt-add-repository",
+            "--yes",
+            "--no-update",
+            "--uri",
+            "http://archive.ubuntu.com/ubuntu/",
+            "--pocket",
+            "proposed",
+            "--component",
+            "main",
+            "--component",
+            "universe",
+        ],
+        "Failed to add proposed repository",
+    )?;
+    Ok(())
+}
+
+fn disable_proposed_repository(rootfs_dir: &Path) -> Result<()> {
+    run_command(
+        "systemd-nspawn",
+        &[
+            "-D",
+            rootfs_dir.to_str().unwrap(),
+            "apt-add-repository",
+            "--yes",
+            "--uri",
+            "http://archive.ubuntu.com/ubuntu/",
+            "--pocket",
+            "proposed",
+            "--component",
+            "main",
+            "--component",
+            "universe",
+            "--remove",
+        ],
+        "Failed to remove proposed repository",
+    )?;
+    Ok(())
+}
+
+fn add_ppa(rootfs_dir: &Path, ppa: &str) -> Result<()> {
+    run_command(
+        "systemd-nspawn",
+        &[
+            "-D",
+            rootfs_dir.to_str().unwrap(),
+            "apt-add-repository",
+            "--no-update",
+            "--yes",
+            ppa,
+        ],
+        &format!("Failed to add PPA {}", ppa),
+    )?;
+    Ok(())
+}
+
+fn remove_ppa(rootfs_dir: &Path, ppa: &str) -> Result<()> {
+    run_command(
+        "systemd-nspawn",
+        &[
+            "-D",
+            rootfs_dir.to_str().unwrap(),
+            "apt-add-repository",
+            "--yes",
+            "--remove",
+            ppa,
+        ],
+        &format!("Failed to remove PPA {}", ppa),
+    )?;
+    Ok(())
+}
+
+/// Inserts a `signed-by=<keyring_path>` option into a "deb ..."/"deb-src ..." line.
+fn with_signed_by(deb_line: &str, keyring_path: &str) -> String {
+    for prefix in ["deb-src ", "deb "] {
+        if let Some(rest) = deb_line.strip_prefix(prefix) {
+            return format!("{}[signed-by={}] {}", prefix, keyring_path, rest);
+        }
+    }
+    deb_line.to_string()
+}
+
+fn custom_repo_list_path(rootfs_dir: &Path, index: usize) -> std::path::PathBuf {
+    rootfs_dir
+        .join("etc/apt/sources.list.d")
+        .join(format!("custom-repo-{}.list", index))
+}
+
+fn custom_repo_keyring_path(rootfs_dir: &Path, index: usize) -> std::path::PathBuf {
+    rootfs_dir
+        .join("etc/apt/keyrings")
+        .join(format!("custom-repo-{}.gpg", index))
+}
+
+/// Adds the `index`-th custom APT repository, fetching and dearmoring
+/// `apt_key` (a URL or local path) into `etc/apt/keyrings/` if given.
+pub async fn add_custom_repo(
+    rootfs_dir: &Path,
+    index: usize,
+    deb_line: &str,
+    apt_key: Option<&str>,
+) -> Result<()> {
+    let source_line = if let Some(key_source) = apt_key {
+        println!("Fetching APT key {}", key_source);
+        let key_bytes = if key_source.starts_with("http://") || key_source.starts_with("https://")
+        {
+            fetch_bytes(key_source).await?
+        } else {
+            fs::read(key_source)
+                .context(format!("Failed to read APT key file {}", key_source))?
+        };
+
+        let keyring_dir = rootfs_dir.join("etc/apt/keyrings");
+        fs::create_dir_all(&keyring_dir).context("Failed to create etc/apt/keyrings")?;
+
+        let armored_key_path = rootfs_dir
+            .join("tmp")
+            .join(format!("custom-repo-{}.key", index));
+        fs::write(&armored_key_path, &key_bytes).context("Failed to write fetched APT key")?;
+
+        let keyring_path = custom_repo_keyring_path(rootfs_dir, index);
+        run_command(
+            "gpg",
+            &[
+                "--batch",
+                "--yes",
+                "--dearmor",
+                "-o",
+                keyring_path.to_str().unwrap(),
+                armored_key_path.to_str().unwrap(),
+            ],
+            "Failed to dearmor APT key",
+        )?;
+        fs::remove_file(&armored_key_path).context("Failed to remove temporary APT key file")?;
+
+        with_signed_by(
+            deb_line,
+            &keyring_path
+                .strip_prefix(rootfs_dir)
+                .map(|p| Path::new("/").join(p))
+                .unwrap_or_else(|_| keyring_path.clone())
+                .to_string_lossy(),
+        )
+    } else {
+        deb_line.to_string()
+    };
+
+    let list_path = custom_repo_list_path(rootfs_dir, index);
+    fs::create_dir_all(list_path.parent().unwrap())
+        .context("Failed to create etc/apt/sources.list.d")?;
+    fs::write(&list_path, format!("{}\n", source_line))
+        .context("Failed to write custom APT repository file")?;
+
+    Ok(())
+}
+
+/// Removes the `index`-th custom APT repository and its keyring, if any.
+pub fn remove_custom_repo(rootfs_dir: &Path, index: usize) -> Result<()> {
+    let list_path = custom_repo_list_path(rootfs_dir, index);
+    if list_path.exists() {
+        fs::remove_file(&list_path).context("Failed to remove custom APT repository file")?;
+    }
+
+    let keyring_path = custom_repo_keyring_path(rootfs_dir, index);
+    if keyring_path.exists() {
+        fs::remove_file(&keyring_path).context("Failed to remove custom APT keyring")?;
+    }
+
+    Ok(())
+}
+
+/// Configuration for the repositories to enable before installing packages.
+pub struct AptConfig {
+    pub proposed: bool,
+    pub ppas: Vec<String>,
+    pub apt_repos: Vec<String>,
+    pub apt_keys: Vec<Option<String>>,
+}
+
+/// Enables -proposed/PPAs/custom repos, installs `package_names`, then tears
+/// everything back down. Teardown only runs for repos/PPAs/-proposed that
+/// were actually enabled, and always runs (even if enabling a later repo, or
+/// the install itself, fails) so a failed run never leaves custom `.list`
+/// files, keyrings or PPAs baked into the image.
+pub async fn install_packages(
+    rootfs_dir: &Path,
+    package_names: &[String],
+    release: &str,
+    apt_config: &AptConfig,
+) -> Result<()> {
+    let mut proposed_enabled = false;
+    let mut ppas_added: Vec<&String> = Vec::new();
+    let mut repos_added: Vec<usize> = Vec::new();
+
+    let result: Result<()> = async {
+        if apt_config.proposed {
+            println!("Enabling -proposed repository...");
+            enable_proposed_repository(rootfs_dir)?;
+            proposed_enabled = true;
+        }
+
+        for ppa in &apt_config.ppas {
+            println!("Adding ppa {}", ppa);
+            add_ppa(rootfs_dir, ppa)?;
+            ppas_added.push(ppa);
+        }
+
+        for (index, deb_line) in apt_config.apt_repos.iter().enumerate() {
+            println!("Adding custom APT repository: {}", deb_line);
+            let apt_key = apt_config.apt_keys.get(index).and_then(|k| k.as_deref());
+            add_custom_repo(rootfs_dir, index, deb_line, apt_key).await?;
+            repos_added.push(index);
+        }
+
+        let packages: Vec<String> = package_names
+            .iter()
+            .map(|package_name| {
+                if apt_config.proposed {
+                    format!("{}/{}-proposed", package_name, release)
+                } else {
+                    package_name.clone()
+                }
+            })
+            .collect();
+
+        run_command(
+            "systemd-nspawn",
+            &[
+                "-D",
+                rootfs_dir.to_str().unwrap(),
+                "apt-get",
+                "update",
+                "-y",
+            ],
+            &format!("Failed to install package(s) {}", packages.join(", ")),
+        )?;
+
+        let mut install_args = vec![
+            "-D",
+            rootfs_dir.to_str().unwrap(),
+            "apt-get",
+            "install",
+            "-y",
+        ];
+        install_args.extend(packages.iter().map(String::as_str));
+        run_command(
+            "systemd-nspawn",
+            &install_args,
+            &format!("Failed to install package(s) {}", packages.join(", ")),
+        )?;
+
+        Ok(())
+    }
+    .await;
+
+    for index in repos_added.into_iter().rev() {
+        println!("Removing custom APT repository #{}", index);
+        if let Err(err) = remove_custom_repo(rootfs_dir, index) {
+            eprintln!("Failed to remove custom APT repository #{}: {}", index, err);
+        }
+    }
+
+    for ppa in ppas_added.into_iter().rev() {
+        println!("Removing PPA {}", ppa);
+        if let Err(err) = remove_ppa(rootfs_dir, ppa) {
+            eprintln!("Failed to remove PPA {}: {}", ppa, err);
+        }
+    }
+
+    if proposed_enabled {
+        println!("Disabling -proposed repository...");
+        if let Err(err) = disable_proposed_repository(rootfs_dir) {
+            eprintln!("Failed to disable -proposed repository: {}", err);
+        }
+    }
+
+    result
+}
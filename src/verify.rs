@@ -0,0 +1,162 @@
+// verify.rs
+//
+// Boots the finished image under qemu-system-x86_64 with a cloud-init
+// NoCloud seed that runs a check script, then polls the serial console for
+// a sentinel the check prints once it confirms the package is installed.
+
+use crate::run_command;
+use anyhow::{anyhow, Context, Result};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+use tempfile::tempdir;
+
+const SENTINEL: &str = "PROPOSED_PACKAGE_TESTING_VERIFY_OK";
+
+/// The default check: fail unless every package is reported installed by
+/// dpkg and, when `proposed` is set, unless the installed version is the one
+/// `apt-cache madison` reports as coming from `<release>-proposed` (so the
+/// check can't pass on a version the base image or a regular update already
+/// satisfied, without `-proposed` ever being exercised).
+fn default_check_script(package_names: &[String], proposed: bool, release: &str) -> String {
+    let mut script = String::from("#!/bin/sh\nset -e\n");
+    for package_name in package_names {
+        script.push_str(&format!("dpkg -s {} >/dev/null 2>&1\n", package_name));
+
+        if proposed {
+            script.push_str(&format!(
+                "installed_version=$(dpkg-query -W -f='${{Version}}' {package})\n\
+                 proposed_version=$(apt-cache madison {package} | awk -F'|' '$0 ~ /{release}-proposed\\// {{print $2; exit}}' | tr -d ' ')\n\
+                 if [ -z \"$proposed_version\" ]; then\n\
+                 \techo \"No -proposed candidate found for {package}\" >&2\n\
+                 \texit 1\n\
+                 fi\n\
+                 if [ \"$installed_version\" != \"$proposed_version\" ]; then\n\
+                 \techo \"{package}: installed version $installed_version does not match -proposed candidate $proposed_version\" >&2\n\
+                 \texit 1\n\
+                 fi\n",
+                package = package_name,
+                release = release,
+            ));
+        }
+    }
+    script.push_str(&format!("echo {}\n", SENTINEL));
+    script
+}
+
+fn indent(text: &str, spaces: usize) -> String {
+    let prefix = " ".repeat(spaces);
+    text.lines()
+        .map(|line| format!("{}{}\n", prefix, line))
+        .collect()
+}
+
+/// Writes a cloud-init NoCloud seed ISO that runs `check_script` on first boot.
+fn build_seed_iso(seed_dir: &Path, check_script: &str) -> Result<PathBuf> {
+    fs::write(
+        seed_dir.join("meta-data"),
+        "instance-id: proposed-package-testing-verify\nlocal-hostname: verify\n",
+    )
+    .context("Failed to write cloud-init meta-data")?;
+
+    let user_data = format!(
+        "#cloud-config\nwrite_files:\n  - path: /root/verify.sh\n    permissions: '0755'\n    content: |\n{}\nruncmd:\n  - [ /root/verify.sh ]\n",
+        indent(check_script, 6)
+    );
+    fs::write(seed_dir.join("user-data"), user_data).context("Failed to write cloud-init user-data")?;
+
+    let iso_path = seed_dir.join("seed.iso");
+    run_command(
+        "genisoimage",
+        &[
+            "-output",
+            iso_path.to_str().unwrap(),
+            "-volid",
+            "cidata",
+            "-joliet",
+            "-rock",
+            seed_dir.join("user-data").to_str().unwrap(),
+            seed_dir.join("meta-data").to_str().unwrap(),
+        ],
+        "Failed to build cloud-init seed ISO",
+    )?;
+
+    Ok(iso_path)
+}
+
+/// Boots `image_path` under QEMU and fails if `SENTINEL` never appears on the
+/// serial console within `timeout`, meaning the check script never confirmed
+/// the package(s) were installed (or the instance never finished booting).
+pub fn verify_image(
+    image_path: &Path,
+    image_format: &str,
+    package_names: &[String],
+    proposed: bool,
+    release: &str,
+    timeout: Duration,
+    check_script_path: Option<&Path>,
+) -> Result<()> {
+    let work_dir = tempdir().context("Failed to create verify working directory")?;
+
+    let check_script = match check_script_path {
+        Some(path) => fs::read_to_string(path)
+            .context(format!("Failed to read verify script {}", path.display()))?,
+        None => default_check_script(package_names, proposed, release),
+    };
+
+    let seed_iso = build_seed_iso(work_dir.path(), &check_script)?;
+    let serial_log = work_dir.path().join("console.log");
+
+    let mut args = vec![
+        "-m".to_string(),
+        "2048".to_string(),
+        "-drive".to_string(),
+        format!("file={},if=virtio,format={}", image_path.display(), image_format),
+        "-drive".to_string(),
+        format!("file={},if=virtio,format=raw", seed_iso.display()),
+        "-serial".to_string(),
+        format!("file:{}", serial_log.display()),
+        "-nographic".to_string(),
+    ];
+    if Path::new("/dev/kvm").exists() {
+        args.push("-enable-kvm".to_string());
+    }
+
+    println!("Booting image under qemu-system-x86_64 to verify package installation");
+    let mut qemu = Command::new("qemu-system-x86_64")
+        .args(&args)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .context("Failed to launch qemu-system-x86_64")?;
+
+    let deadline = Instant::now() + timeout;
+    let result = loop {
+        let console_output = fs::read_to_string(&serial_log).unwrap_or_default();
+        if console_output.contains(SENTINEL) {
+            break Ok(());
+        }
+
+        if let Some(status) = qemu.try_wait().context("Failed to poll qemu-system-x86_64")? {
+            break Err(anyhow!(
+                "qemu-system-x86_64 exited early with status {:?} before verification completed",
+                status
+            ));
+        }
+
+        if Instant::now() >= deadline {
+            break Err(anyhow!(
+                "Timed out after {:?} waiting for the verification sentinel on the serial console",
+                timeout
+            ));
+        }
+
+        std::thread::sleep(Duration::from_secs(1));
+    };
+
+    let _ = qemu.kill();
+    let _ = qemu.wait();
+
+    result
+}